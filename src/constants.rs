@@ -3,8 +3,49 @@ use build_time::build_time_utc;
 pub static PROGRAM_NAME: &str = "RedisMultiplexer";
 pub static STATISTICS_SECONDS: u128 = 10;
 pub static DEFAULT_CHECK_SECONDS: u64 = 1;
+pub static DEFAULT_CLAIM_IDLE: u64 = 60000;
+pub static DEFAULT_BUFFER_SIZE: usize = 100;
+pub static DEFAULT_BUFFER_DELAY: u64 = 100;
+pub static DEFAULT_POOL_IDLE_TIMEOUT: u64 = 600;
+pub static DEFAULT_POOL_TIMEOUT: u64 = 5;
+pub static DEFAULT_FAILOVER_STABLE: u64 = 30;
+pub static DEFAULT_BACKOFF: u64 = 500;
+pub static DEFAULT_BACKOFF_MAX: u64 = 30000;
+pub static DEFAULT_RETRY_BACKOFF: u64 = 100;
+pub static DEFAULT_RETRY_BACKOFF_MAX: u64 = 5000;
+pub static RETRY_PENDING_MAX: usize = 10000;
+pub static DEFAULT_REQUEUE_LIMIT: u32 = 10;
+pub static DEFAULT_REQUEUE_BACKOFF: u64 = 100;
+pub static DEFAULT_REQUEUE_BACKOFF_MAX: u64 = 5000;
+pub static REQUEUE_TRACKED_MAX: usize = 10000;
+pub static PROBE_TIMEOUT: u64 = 1000;
+pub static SENTINEL_TIMEOUT: u64 = 1000;
+pub static KEY_IDLE: u64 = 3600;
+pub static DEFAULT_SPILL_SIZE: u64 = 1073741824;
+pub static DEFAULT_SPILL_SEGMENT: u64 = 16777216;
+pub static SPILL_REPLAY_BATCH: usize = 1000;
 pub static MAX_QUEUE_SIZE: isize = 100000000;
 
+// Move a batch of packages from a list to its processing list atomically
+pub static LIST_BATCH_MOVE: &str = "
+local items = redis.call('LRANGE', KEYS[1], 0, tonumber(ARGV[1]) - 1)
+if #items > 0 then
+    redis.call('LTRIM', KEYS[1], #items, -1)
+    redis.call('RPUSH', KEYS[2], unpack(items))
+end
+return items
+";
+
+// Remove packages from the processing list and put them back at the head of the source queue atomically (in the same order)
+pub static LIST_REQUEUE: &str = "
+for i = 1, #ARGV do
+    redis.call('LREM', KEYS[1], 1, ARGV[i])
+end
+for i = #ARGV, 1, -1 do
+    redis.call('LPUSH', KEYS[2], ARGV[i])
+end
+";
+
 // Autofields
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const BUILD_DATE: &str = build_time_utc!();
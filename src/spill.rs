@@ -0,0 +1,491 @@
+use std::cmp;
+use std::fs;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Packages kept on disk while a client can't take them, as an append-only log split in segments
+///
+/// Every package is written as: channel length (u32), data length (u32), channel, data.
+/// Segments are numbered, the oldest one is replayed and removed once all its packages were sent.
+pub struct Spill {
+    dir: PathBuf,               // Folder with the segments
+    segment_size: u64,          // Bytes written to a segment before starting a new one
+    max_size: u64,              // Bytes waiting in all segments together
+    size: u64,                  // Bytes in all segments (replayed or not)
+    first: u64,                 // Segment being replayed
+    last: u64,                  // Segment being written
+    last_size: u64,             // Bytes in the segment being written
+    offset: u64,                // Position of the next package in the segment being replayed
+    reader: Option<BufReader<fs::File>>,        // Open segment being replayed (at offset)
+    record: Option<(String, Vec<u8>)>,          // Next package (already read)
+    pub full: bool,             // The last package didn't fit
+    pub skipped: Vec<String>,   // Why corrupt segments were given up (not reported yet)
+}
+
+impl Spill {
+
+    /// Open the spill in the folder, packages left from the last run are kept
+    pub fn open(dir: &Path, segment_size: u64, max_size: u64) -> Result<Spill, String> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("couldn't create folder '{}': {}", dir.display(), e));
+        }
+
+        // Find segments
+        let mut segments: Vec<u64> = Vec::new();
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("couldn't read folder '{}': {}", dir.display(), e)),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("spill") {
+                if let Some(n) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+                    segments.push(n);
+                }
+            }
+        }
+        segments.sort();
+
+        let mut spill = Spill{
+            dir: dir.to_path_buf(),
+            segment_size,
+            max_size,
+            size: 0,
+            first: *segments.first().unwrap_or(&1),
+            last: *segments.last().unwrap_or(&1),
+            last_size: 0,
+            offset: 0,
+            reader: None,
+            record: None,
+            full: false,
+            skipped: Vec::new(),
+        };
+        for n in &segments {
+            spill.size += spill.segment_len(*n)?;
+        }
+
+        // A package may be half written if we were stopped while writing it
+        let valid = spill.valid_len(spill.last)?;
+        let len = spill.segment_len(spill.last)?;
+        if valid < len {
+            let file = fs::OpenOptions::new().write(true).open(spill.segment_path(spill.last));
+            if let Err(e) = file.and_then(|f| f.set_len(valid)) {
+                return Err(format!("couldn't repair segment {}: {}", spill.last, e));
+            }
+            spill.size -= len - valid;
+        }
+        spill.last_size = valid;
+
+        // Where we were replaying
+        if let Ok(position) = fs::read_to_string(spill.position_path()) {
+            let fields: Vec<u64> = position.split_whitespace().filter_map(|f| f.parse::<u64>().ok()).collect();
+            if (fields.len() == 2) && (fields[0] == spill.first) {
+                spill.offset = cmp::min(fields[1], spill.segment_len(spill.first)?);
+            }
+        }
+
+        return Ok(spill);
+    }
+
+    /// Check if all packages were replayed
+    pub fn is_empty(&self) -> bool {
+        return self.size == self.offset;
+    }
+
+    /// Keep a package at the end of the log (false if it doesn't fit)
+    pub fn push(&mut self, channel: &str, data: &[u8]) -> Result<bool, String> {
+        let len = 8 + (channel.len() as u64) + (data.len() as u64);
+        if (self.size - self.offset + len) > self.max_size {
+            self.full = true;
+            return Ok(false);
+        }
+
+        // Start again once everything was replayed, so the disk is freed
+        if self.is_empty() && (self.offset > 0) {
+            self.remove_segment(self.first)?;
+            self.first = self.last + 1;
+            self.last = self.first;
+            self.last_size = 0;
+            self.size = 0;
+            self.offset = 0;
+            self.save_position()?;
+        } else if self.last_size >= self.segment_size {
+            self.last += 1;
+            self.last_size = 0;
+        }
+
+        // The package must be on disk before the client is told it was kept
+        self.append(channel, data, true)?;
+        return Ok(true);
+    }
+
+    /// Move all packages of another spill (left by a child that doesn't exist anymore) to this one, the folder is removed
+    pub fn adopt(&mut self, dir: &Path) -> Result<u64, String> {
+        let mut orphan = Spill::open(dir, u64::MAX, u64::MAX)?;
+        let from = self.last;
+        let mut total: u64 = 0;
+        while let Some((channel, data)) = orphan.peek()? {
+            if self.last_size >= self.segment_size {
+                self.last += 1;
+                self.last_size = 0;
+            }
+            // Nothing is dropped even if the spill is full, and everything is synced at once
+            self.append(&channel, &data, false)?;
+
+            // Like pop() without saving the position, the folder goes away
+            orphan.record = None;
+            orphan.offset += 8 + (channel.len() as u64) + (data.len() as u64);
+            total += 1;
+        }
+        self.skipped.append(&mut orphan.skipped);
+        if total > 0 {
+            for n in from..=self.last {
+                if let Err(e) = fs::File::open(self.segment_path(n)).and_then(|f| f.sync_all()) {
+                    return Err(format!("couldn't sync segment {}: {}", n, e));
+                }
+            }
+            self.sync_dir()?;
+        }
+
+        // If we are stopped before this, the packages will be adopted twice (but not lost)
+        match fs::remove_dir_all(dir) {
+            Ok(_) => return Ok(total),
+            Err(e) => return Err(format!("couldn't remove folder '{}': {}", dir.display(), e)),
+        }
+    }
+
+    /// Next package to be replayed: (channel, data)
+    pub fn peek(&mut self) -> Result<Option<(String, Vec<u8>)>, String> {
+        if self.record.is_some() {
+            return Ok(self.record.clone());
+        }
+        while !self.is_empty() {
+
+            // The segment was replayed completely, go on with the next one
+            let len = self.segment_len(self.first)?;
+            if self.offset >= len {
+                if self.first >= self.last {
+                    self.skipped.push(format!("segment {} is shorter than expected", self.first));
+                }
+                self.skip_segment(len)?;
+                continue;
+            }
+
+            // Read the package (a corrupt one loses the rest of its segment, not the whole spill)
+            match self.read_record(len) {
+                Ok(record) => {
+                    self.record = Some(record);
+                    return Ok(self.record.clone());
+                },
+                Err(e) if (e.kind() == ErrorKind::UnexpectedEof) || (e.kind() == ErrorKind::InvalidData) => {
+                    self.skipped.push(format!("segment {} is corrupt at {}, skipped {} bytes: {}", self.first, self.offset, len - self.offset, e));
+                    self.skip_segment(len)?;
+                },
+                Err(e) => {
+                    self.reader = None;
+                    return Err(format!("couldn't read segment {}: {}", self.first, e));
+                },
+            }
+        }
+        return Ok(None);
+    }
+
+    /// The package from peek() was sent, forget it
+    pub fn pop(&mut self) -> Result<(), String> {
+        if let Some((channel, data)) = self.record.take() {
+            self.offset += 8 + (channel.len() as u64) + (data.len() as u64);
+            self.full = false;
+            self.save_position()?;
+        }
+        return Ok(());
+    }
+
+    /// Read the package at offset of the first segment (len bytes long)
+    fn read_record(&mut self, len: u64) -> std::io::Result<(String, Vec<u8>)> {
+        if self.reader.is_none() {
+            let mut file = fs::File::open(self.segment_path(self.first))?;
+            file.seek(SeekFrom::Start(self.offset))?;
+            self.reader = Some(BufReader::new(file));
+        }
+        let reader = self.reader.as_mut().unwrap();
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        let channel_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let data_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        if (self.offset + 8 + channel_len + data_len) > len {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "package is longer than the segment"));
+        }
+        let mut channel = vec![0u8; channel_len as usize];
+        let mut data = vec![0u8; data_len as usize];
+        reader.read_exact(&mut channel)?;
+        reader.read_exact(&mut data)?;
+        match String::from_utf8(channel) {
+            Ok(channel) => return Ok((channel, data)),
+            Err(_) => return Err(std::io::Error::new(ErrorKind::InvalidData, "channel is not valid UTF8")),
+        }
+    }
+
+    /// Write a package at the end of the last segment
+    fn append(&mut self, channel: &str, data: &[u8], sync: bool) -> Result<(), String> {
+        let len = 8 + (channel.len() as u64) + (data.len() as u64);
+        let mut record: Vec<u8> = Vec::with_capacity(len as usize);
+        record.extend_from_slice(&(channel.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(channel.as_bytes());
+        record.extend_from_slice(data);
+        let path = self.segment_path(self.last);
+        let result = fs::OpenOptions::new().create(true).append(true).open(&path).and_then(|mut f| {
+            f.write_all(&record)?;
+            if sync {
+                f.sync_data()?;
+            }
+            return Ok(());
+        });
+        if let Err(e) = result {
+            return Err(format!("couldn't write to '{}': {}", path.display(), e));
+        }
+
+        // A new segment must be in the folder as well
+        if sync && (self.last_size == 0) {
+            self.sync_dir()?;
+        }
+        self.size += len;
+        self.last_size += len;
+        return Ok(());
+    }
+
+    /// Make sure the list of segments is on disk
+    fn sync_dir(&self) -> Result<(), String> {
+        match fs::File::open(&self.dir).and_then(|f| f.sync_all()) {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(format!("couldn't sync folder '{}': {}", self.dir.display(), e)),
+        }
+    }
+
+    /// Forget the first segment (replayed or corrupt) and go on with the next one
+    fn skip_segment(&mut self, len: u64) -> Result<(), String> {
+        self.remove_segment(self.first)?;
+        self.first += 1;
+        if self.first > self.last {
+            // Nothing is left
+            self.last = self.first;
+            self.last_size = 0;
+            self.size = 0;
+        } else {
+            self.size = self.size.saturating_sub(len);
+        }
+        self.offset = 0;
+        return self.save_position();
+    }
+
+    /// Bytes of the segment that hold complete packages
+    fn valid_len(&self, n: u64) -> Result<u64, String> {
+        let file = match fs::File::open(self.segment_path(n)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("couldn't open segment {}: {}", n, e)),
+        };
+        let mut reader = BufReader::new(file);
+        let mut valid: u64 = 0;
+        loop {
+            let mut header = [0u8; 8];
+            if reader.read_exact(&mut header).is_err() {
+                return Ok(valid);
+            }
+            let len = (u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64) + (u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64);
+            let skipped = match std::io::copy(&mut reader.by_ref().take(len), &mut std::io::sink()) {
+                Ok(s) => s,
+                Err(_) => return Ok(valid),
+            };
+            if skipped < len {
+                return Ok(valid);
+            }
+            valid += 8 + len;
+        }
+    }
+
+    /// Size of a segment (0 if it doesn't exist)
+    fn segment_len(&self, n: u64) -> Result<u64, String> {
+        match fs::metadata(self.segment_path(n)) {
+            Ok(m) => return Ok(m.len()),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("couldn't check segment {}: {}", n, e)),
+        }
+    }
+
+    /// Remove a replayed segment
+    fn remove_segment(&mut self, n: u64) -> Result<(), String> {
+        self.reader = None;
+        match fs::remove_file(self.segment_path(n)) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("couldn't remove segment {}: {}", n, e)),
+        }
+    }
+
+    /// Remember where we are replaying, so a restart doesn't send packages twice
+    fn save_position(&self) -> Result<(), String> {
+        match fs::write(self.position_path(), format!("{} {}", self.first, self.offset)) {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(format!("couldn't save position: {}", e)),
+        }
+    }
+
+    fn segment_path(&self, n: u64) -> PathBuf {
+        return self.dir.join(format!("{:020}.spill", n));
+    }
+
+    fn position_path(&self) -> PathBuf {
+        return self.dir.join("position");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("redismultiplexer-spill-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        return dir;
+    }
+
+    fn drain(spill: &mut Spill) -> Vec<(String, Vec<u8>)> {
+        let mut records = Vec::new();
+        while let Some(record) = spill.peek().unwrap() {
+            records.push(record);
+            spill.pop().unwrap();
+        }
+        return records;
+    }
+
+    #[test]
+    fn push_peek_pop_and_reopen() {
+        let dir = test_dir("reopen");
+        let mut spill = Spill::open(&dir, 1024, 1024 * 1024).unwrap();
+        assert!(spill.is_empty());
+        for n in 0..3 {
+            assert_eq!(spill.push("q", format!("p{}", n).as_bytes()), Ok(true));
+        }
+
+        // Peek doesn't move forward until pop
+        assert_eq!(spill.peek().unwrap(), Some(("q".to_string(), b"p0".to_vec())));
+        assert_eq!(spill.peek().unwrap(), Some(("q".to_string(), b"p0".to_vec())));
+        spill.pop().unwrap();
+        drop(spill);
+
+        // A restart goes on where we were
+        let mut spill = Spill::open(&dir, 1024, 1024 * 1024).unwrap();
+        spill.push("other", b"p3").unwrap();
+        assert_eq!(drain(&mut spill), vec![("q".to_string(), b"p1".to_vec()), ("q".to_string(), b"p2".to_vec()), ("other".to_string(), b"p3".to_vec())]);
+        assert!(spill.is_empty());
+        drop(spill);
+
+        let mut spill = Spill::open(&dir, 1024, 1024 * 1024).unwrap();
+        assert!(spill.is_empty());
+        assert_eq!(spill.peek().unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segments_are_removed_once_replayed() {
+        let dir = test_dir("segments");
+        let mut spill = Spill::open(&dir, 20, 1024 * 1024).unwrap();
+        for n in 0..10 {
+            spill.push("q", format!("package{}", n).as_bytes()).unwrap();
+        }
+        let segments = |dir: &Path| fs::read_dir(dir).unwrap().flatten().filter(|e| e.path().extension().is_some()).count();
+        assert_eq!(segments(&dir), 5);
+        let records = drain(&mut spill);
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[9].1, b"package9".to_vec());
+        assert_eq!(segments(&dir), 1);
+
+        // Once everything was replayed the disk is freed
+        spill.push("q", b"again").unwrap();
+        assert_eq!(segments(&dir), 1);
+        assert_eq!(drain(&mut spill), vec![("q".to_string(), b"again".to_vec())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn full_spill_drops_packages() {
+        let dir = test_dir("full");
+        let mut spill = Spill::open(&dir, 1024, 30).unwrap();
+        assert_eq!(spill.push("q", b"0123456789"), Ok(true));
+        assert_eq!(spill.push("q", b"0123456789"), Ok(false));
+        assert!(spill.full);
+        assert_eq!(drain(&mut spill).len(), 1);
+        assert!(!spill.full);
+        assert_eq!(spill.push("q", b"0123456789"), Ok(true));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_tail_is_repaired() {
+        let dir = test_dir("truncated");
+        let mut spill = Spill::open(&dir, 1024, 1024 * 1024).unwrap();
+        spill.push("q", b"first").unwrap();
+        spill.push("q", b"second").unwrap();
+        let path = spill.segment_path(spill.last);
+        let len = fs::metadata(&path).unwrap().len();
+        drop(spill);
+
+        // Stopped while writing the third one
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 1, 0, 0, 0, 9, b'q', b't', b'h']).unwrap();
+        drop(file);
+
+        let mut spill = Spill::open(&dir, 1024, 1024 * 1024).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        spill.push("q", b"third").unwrap();
+        let records: Vec<Vec<u8>> = drain(&mut spill).into_iter().map(|(_, data)| data).collect();
+        assert_eq!(records, vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+        assert!(spill.skipped.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_segment_is_skipped() {
+        let dir = test_dir("corrupt");
+        let mut spill = Spill::open(&dir, 20, 1024 * 1024).unwrap();
+        spill.push("q", b"first").unwrap();
+        spill.push("q", b"lost").unwrap();
+        spill.push("q", b"kept").unwrap();
+        drop(spill);
+
+        // The second package of the first segment says it is huge
+        let path = dir.join(format!("{:020}.spill", 1));
+        let mut data = fs::read(&path).unwrap();
+        data[14..18].copy_from_slice(&u32::MAX.to_be_bytes());
+        fs::write(&path, &data).unwrap();
+
+        let mut spill = Spill::open(&dir, 20, 1024 * 1024).unwrap();
+        let records: Vec<Vec<u8>> = drain(&mut spill).into_iter().map(|(_, data)| data).collect();
+        assert_eq!(records, vec![b"first".to_vec(), b"kept".to_vec()]);
+        assert_eq!(spill.skipped.len(), 1);
+        assert!(spill.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn adopt_moves_all_packages() {
+        let dir = test_dir("adopt");
+        let mut orphan = Spill::open(&dir.join("1"), 20, 1024 * 1024).unwrap();
+        for n in 0..5 {
+            orphan.push("q", format!("orphan{}", n).as_bytes()).unwrap();
+        }
+        orphan.peek().unwrap();
+        orphan.pop().unwrap();
+        drop(orphan);
+
+        // Packages are kept even if they don't fit, the ones already replayed are not
+        let mut spill = Spill::open(&dir.join("0"), 20, 20).unwrap();
+        assert_eq!(spill.push("q", b"mine"), Ok(true));
+        assert_eq!(spill.adopt(&dir.join("1")), Ok(4));
+        assert!(!dir.join("1").exists());
+        let records: Vec<Vec<u8>> = drain(&mut spill).into_iter().map(|(_, data)| data).collect();
+        assert_eq!(records, vec![b"mine".to_vec(), b"orphan1".to_vec(), b"orphan2".to_vec(), b"orphan3".to_vec(), b"orphan4".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}